@@ -1,31 +1,73 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::{max, min};
 use std::convert::TryFrom;
 use std::rc::{Rc, Weak};
-use std::time::Duration;
-
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+/// The finest interval a `Period` can represent, in nanoseconds.
+const PERIOD_MIN_NANOS: u64 = 250_000;
+/// The number of buckets per doubling of the interval, i.e. the bucket
+/// base is `2^(1/PERIOD_BUCKETS_PER_OCTAVE)`.
+const PERIOD_BUCKETS_PER_OCTAVE: u32 = 4;
+
+/// A quantized timer period.
+///
+/// Rather than track the exact duration a caller asks for, this reduces a
+/// `Duration` to one of a fixed number of logarithmically-spaced buckets
+/// between `PERIOD_MIN_NANOS` and 16ms. A linear, whole-millisecond scale
+/// wastes resolution on short periods (where it matters most) and is
+/// needlessly precise about long ones (where it doesn't matter at all), so
+/// buckets are spaced at a constant ratio instead of a constant difference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Period(u8);
 impl Period {
-    const MAX: Period = Period(16);
+    const COUNT: u8 = 25;
+    const MAX: Period = Period(Self::COUNT);
     const MIN: Period = Period(1);
 
+    /// The duration represented by this bucket, in nanoseconds.
+    fn nanos(&self) -> u64 {
+        let i = f64::from(self.0 - Self::MIN.0);
+        let scale = 2f64.powf(i / f64::from(PERIOD_BUCKETS_PER_OCTAVE));
+        (PERIOD_MIN_NANOS as f64 * scale) as u64
+    }
+
     #[cfg(windows)]
     fn as_uint(&self) -> win::UINT {
-        win::UINT::from(self.0)
+        // timeBeginPeriod/timeEndPeriod only understand whole milliseconds;
+        // round up, but never drop below 1ms.
+        let ms = (self.nanos() + 999_999) / 1_000_000;
+        win::UINT::try_from(max(1, ms)).unwrap_or(win::UINT::MAX)
     }
 
     #[cfg(target_os = "macos")]
     fn scaled(&self, scale: f64) -> f64 {
-        scale * f64::from(self.0)
+        scale * (self.nanos() as f64 / 1_000_000.0)
+    }
+
+    /// The timer slack to request for this period, in nanoseconds: a few
+    /// microseconds at the finest bucket, scaling gently with coarser ones.
+    /// Callers must still clamp this against the original slack (see
+    /// `HrTime::slack`), since a long period would otherwise widen it past
+    /// what the kernel already had configured.
+    #[cfg(target_os = "linux")]
+    fn slack_nanos(&self) -> u64 {
+        max(2_000, self.nanos() / 32)
     }
 }
 
 impl From<Duration> for Period {
     fn from(p: Duration) -> Self {
-        let rounded =
-            u8::try_from((p + Duration::from_nanos(999_999)).as_millis()).unwrap_or(Self::MAX.0);
-        Self(max(Self::MIN.0, min(rounded, Self::MAX.0)))
+        let nanos = u64::try_from(p.as_nanos()).unwrap_or(u64::MAX);
+        if nanos <= PERIOD_MIN_NANOS {
+            return Self::MIN;
+        }
+        let ratio = nanos as f64 / PERIOD_MIN_NANOS as f64;
+        let i = (ratio.log2() * f64::from(PERIOD_BUCKETS_PER_OCTAVE)).floor();
+        let i = if i.is_finite() { i as i64 } else { i64::from(Self::COUNT) };
+        let i = i.clamp(0, i64::from(Self::COUNT - Self::MIN.0));
+        Self(Self::MIN.0 + i as u8)
     }
 }
 
@@ -181,12 +223,12 @@ mod mac {
     }
 
     /// Create a realtime policy and set it.
-    pub fn set_realtime(base: f64) {
+    pub fn set_realtime(base: f64, constraints: super::RealtimeConstraints) {
         let policy = thread_time_constraint_policy {
-            period: base as u32,               // Base interval
-            computation: (base * 5.0) as u32,  // Generous allowance
-            constraint: (base * 100.0) as u32, // Even more generous
-            preemptible: 1,
+            period: base as u32, // Base interval
+            computation: (base * constraints.computation_ratio) as u32,
+            constraint: (base * constraints.constraint_ratio) as u32,
+            preemptible: boolean_t::from(constraints.preemptible),
         };
         set_thread_policy(policy);
     }
@@ -210,10 +252,83 @@ mod mac {
     }
 }
 
+#[cfg(target_os = "linux")]
+mod linux {
+    // prctl(2) lets us shrink the per-thread timer slack so that
+    // std::thread::sleep (and anything else the kernel services via
+    // hrtimers) wakes close to the requested time instead of being
+    // coalesced into the ~50µs-1ms default window.
+    use std::os::raw::{c_int, c_ulong};
+
+    const PR_SET_TIMERSLACK: c_int = 29;
+    const PR_GET_TIMERSLACK: c_int = 30;
+
+    extern "C" {
+        fn prctl(option: c_int, arg2: c_ulong, arg3: c_ulong, arg4: c_ulong, arg5: c_ulong)
+            -> c_int;
+    }
+
+    /// Fetch the current thread's timer slack, in nanoseconds, or `None` if
+    /// this kernel/sandbox doesn't support asking (old kernels, seccomp,
+    /// gVisor-style runtimes). This is a best-effort perf tweak, not a
+    /// correctness requirement, so a failure here must not be fatal.
+    pub fn get_timerslack() -> Option<u64> {
+        let r = unsafe { prctl(PR_GET_TIMERSLACK, 0, 0, 0, 0) };
+        if r >= 0 {
+            Some(r as u64)
+        } else {
+            None
+        }
+    }
+
+    /// Set the current thread's timer slack, in nanoseconds. Best-effort:
+    /// if the kernel/sandbox refuses, there's nothing to do but leave the
+    /// slack as it was.
+    pub fn set_timerslack(nanos: u64) {
+        unsafe {
+            prctl(PR_SET_TIMERSLACK, nanos as c_ulong, 0, 0, 0);
+        }
+    }
+}
+
+/// Configures the macOS realtime thread scheduling policy applied while a
+/// `HrPeriod` is active. `computation_ratio` and `constraint_ratio` scale
+/// the active `Period` to get `thread_time_constraint_policy`'s
+/// `computation` and `constraint` fields; the defaults reproduce the
+/// generous budget this crate used before the ratios were configurable.
+#[cfg(target_os = "macos")]
+#[derive(Clone, Copy)]
+pub struct RealtimeConstraints {
+    pub computation_ratio: f64,
+    pub constraint_ratio: f64,
+    pub preemptible: bool,
+}
+
+#[cfg(target_os = "macos")]
+impl Default for RealtimeConstraints {
+    fn default() -> Self {
+        Self {
+            computation_ratio: 5.0,
+            constraint_ratio: 100.0,
+            preemptible: true,
+        }
+    }
+}
+
 /// A handle for a high-resolution timer of a specific period.
 pub struct HrPeriod {
     period: Period,
     hrt: Rc<RefCell<HrTime>>,
+    /// A running high-percentile estimate of how long the OS scheduler
+    /// tends to oversleep past a `sleep_until` target on this host.
+    /// Self-tunes from observed overshoot, biased to track the tail rather
+    /// than the mean so that most wakeups land at or before the deadline.
+    overshoot: Cell<Duration>,
+    /// The constraints this handle overrode via [`HrTime::with_constraints`],
+    /// if any, so they can be restored once this handle goes away instead
+    /// of leaking into whichever `HrPeriod` happens to activate next.
+    #[cfg(target_os = "macos")]
+    prev_constraints: Option<RealtimeConstraints>,
 }
 
 impl HrPeriod {
@@ -225,12 +340,73 @@ impl HrPeriod {
             self.period = new;
             b.periods.add(self.period);
             b.update();
+            self.overshoot.set(Duration::ZERO);
         }
     }
+
+    /// Sleep until `deadline`, combining `std::thread::sleep` with a final
+    /// busy-wait so that the wakeup lands as close to `deadline` as the
+    /// quantized `Period` this handle negotiated allows.
+    ///
+    /// The OS sleep is cut short by a guard of roughly one period plus the
+    /// measured `overshoot` estimate; the busy-wait then closes whatever gap
+    /// remains. Each call that reaches the OS sleep feeds the observed
+    /// overshoot back in, growing the estimate quickly when a sample exceeds
+    /// it and decaying it slowly otherwise, so it tracks a conservative,
+    /// high-percentile oversleep rather than settling on the mean of a
+    /// right-skewed distribution.
+    pub fn sleep_until(&self, deadline: Instant) {
+        let guard = Duration::from_nanos(self.period.nanos()) + self.overshoot.get();
+        let target = deadline.checked_sub(guard).unwrap_or(deadline);
+        let slept = if let Some(remaining) = target.checked_duration_since(Instant::now()) {
+            sleep(remaining);
+            true
+        } else {
+            false
+        };
+        let woke = Instant::now();
+
+        while Instant::now() < deadline {}
+
+        if !slept {
+            // The target had already passed, so there is no oversleep to learn from.
+            return;
+        }
+
+        const GROW: f64 = 0.5;
+        const DECAY: f64 = 0.05;
+        let overshoot = woke.saturating_duration_since(target);
+        let prev = self.overshoot.get().as_nanos() as f64;
+        let sample = overshoot.as_nanos() as f64;
+        let rate = if sample > prev { GROW } else { DECAY };
+        let smoothed = prev + rate * (sample - prev);
+        self.overshoot.set(Duration::from_nanos(smoothed.max(0.0) as u64));
+    }
+
+    /// Sleep for `duration`, see [`HrPeriod::sleep_until`].
+    pub fn sleep(&self, duration: Duration) {
+        self.sleep_until(Instant::now() + duration);
+    }
+
+    /// The rate, in Hz, of the quantized period this handle negotiated,
+    /// i.e. what rate a caller of [`HrTime::get_hz`] actually got once the
+    /// request was clamped to the representable range.
+    pub fn frequency(&self) -> u32 {
+        u32::try_from(1_000_000_000 / max(1, self.period.nanos())).unwrap_or(u32::MAX)
+    }
 }
 
 impl Drop for HrPeriod {
     fn drop(&mut self) {
+        #[cfg(target_os = "macos")]
+        if let Some(prev) = self.prev_constraints.take() {
+            let mut b = self.hrt.borrow_mut();
+            b.constraints = prev;
+            if b.active.is_some() {
+                b.start();
+            }
+        }
+
         self.hrt.borrow_mut().remove(self.period);
     }
 }
@@ -244,10 +420,17 @@ pub struct HrTime {
     scale: f64,
     #[cfg(target_os = "macos")]
     deflt: mac::thread_time_constraint_policy,
+    #[cfg(target_os = "macos")]
+    constraints: RealtimeConstraints,
+
+    /// The slack to restore once no `Period` is active, or `None` if it
+    /// couldn't be read (in which case we also never change it).
+    #[cfg(target_os = "linux")]
+    slack: Option<u64>,
 }
 impl HrTime {
     fn new() -> Self {
-        let hrt = HrTime {
+        HrTime {
             periods: PeriodSet::default(),
             active: None,
 
@@ -255,14 +438,18 @@ impl HrTime {
             scale: mac::get_scale(),
             #[cfg(target_os = "macos")]
             deflt: mac::get_default_policy(),
-        };
-        hrt
+            #[cfg(target_os = "macos")]
+            constraints: RealtimeConstraints::default(),
+
+            #[cfg(target_os = "linux")]
+            slack: linux::get_timerslack(),
+        }
     }
 
     fn start(&self) {
         #[cfg(target_os = "macos")]
         if let Some(p) = self.active {
-            mac::set_realtime(p.scaled(self.scale));
+            mac::set_realtime(p.scaled(self.scale), self.constraints);
         } else {
             mac::set_thread_policy(self.deflt.clone());
         }
@@ -271,6 +458,15 @@ impl HrTime {
         if let Some(p) = self.active {
             assert_eq!(0, unsafe { win::timeBeginPeriod(p.as_uint()) });
         }
+
+        #[cfg(target_os = "linux")]
+        if let Some(orig) = self.slack {
+            if let Some(p) = self.active {
+                linux::set_timerslack(min(p.slack_nanos(), orig));
+            } else {
+                linux::set_timerslack(orig);
+            }
+        }
     }
 
     fn stop(&self) {
@@ -317,9 +513,40 @@ impl HrTime {
 
             let p = Period::from(period);
             hrt.borrow_mut().add(p);
-            HrPeriod { hrt, period: p }
+            HrPeriod {
+                hrt,
+                period: p,
+                overshoot: Cell::new(Duration::ZERO),
+                #[cfg(target_os = "macos")]
+                prev_constraints: None,
+            }
         })
     }
+
+    /// Acquire a reference to the object, requesting a period that yields
+    /// (as close as the platform allows to) the given frequency, in Hz.
+    pub fn get_hz(hz: u32) -> HrPeriod {
+        Self::get(Duration::from_nanos(1_000_000_000 / u64::from(max(1, hz))))
+    }
+
+    /// Acquire a reference to the object, as with [`HrTime::get`], but using
+    /// the given realtime scheduling constraints instead of the defaults.
+    ///
+    /// The prior constraints are restored once the returned handle drops,
+    /// so this override doesn't leak into whichever `HrPeriod` activates
+    /// next on this thread.
+    #[cfg(target_os = "macos")]
+    pub fn with_constraints(period: Duration, constraints: RealtimeConstraints) -> HrPeriod {
+        let mut hrp = Self::get(period);
+        let mut b = hrp.hrt.borrow_mut();
+        hrp.prev_constraints = Some(b.constraints);
+        b.constraints = constraints;
+        if b.active.is_some() {
+            b.start();
+        }
+        drop(b);
+        hrp
+    }
 }
 
 impl Drop for HrTime {
@@ -330,12 +557,137 @@ impl Drop for HrTime {
         if self.active.is_some() {
             mac::set_thread_policy(self.deflt);
         }
+
+        #[cfg(target_os = "linux")]
+        if self.active.is_some() {
+            if let Some(slack) = self.slack {
+                linux::set_timerslack(slack);
+            }
+        }
+    }
+}
+
+/// Summary statistics produced by [`HrStats::summary`].
+#[derive(Debug, Clone, Copy)]
+pub struct HrSummary {
+    pub count: u64,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+}
+
+/// Accumulates sleep lag samples and reports latency percentiles.
+///
+/// Samples are bucketed into a fixed microsecond-wide histogram up to a
+/// configurable ceiling (with an overflow bucket for anything beyond that),
+/// so percentile estimation stays O(1) in the number of samples recorded.
+pub struct HrStats {
+    buckets: Vec<u64>,
+    overflow: u64,
+    count: u64,
+    sum: Duration,
+    min: Duration,
+    max: Duration,
+}
+
+impl HrStats {
+    /// Create an accumulator whose histogram covers lag up to `ceiling`, in
+    /// one-microsecond buckets.
+    pub fn new(ceiling: Duration) -> Self {
+        let buckets = max(1, ceiling.as_micros().min(u128::from(u32::MAX)) as usize);
+        Self {
+            buckets: vec![0; buckets],
+            overflow: 0,
+            count: 0,
+            sum: Duration::ZERO,
+            min: Duration::MAX,
+            max: Duration::ZERO,
+        }
+    }
+
+    /// Record one sample: a sleep intended to last `intended` actually took
+    /// `actual`. The lag, `actual - intended` (saturating at zero), is what
+    /// gets bucketed.
+    pub fn record(&mut self, intended: Duration, actual: Duration) {
+        let lag = actual.saturating_sub(intended);
+        self.count += 1;
+        self.sum += lag;
+        self.min = self.min.min(lag);
+        self.max = self.max.max(lag);
+
+        match usize::try_from(lag.as_micros()) {
+            Ok(i) if i < self.buckets.len() => self.buckets[i] += 1,
+            _ => self.overflow += 1,
+        }
+    }
+
+    /// Find the lag at which `quantile` (in `[0, 1]`) of samples recorded so
+    /// far fall at or below, estimated from the histogram.
+    fn quantile(&self, quantile: f64) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        let target = (self.count as f64 * quantile).ceil() as u64;
+        let mut seen = 0;
+        for (us, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                return Duration::from_micros(us as u64);
+            }
+        }
+        // The target fell in the overflow bucket: all we know is that it's
+        // past the histogram's ceiling, so report that as a lower bound.
+        Duration::from_micros(self.buckets.len() as u64)
+    }
+
+    /// Compute min/max/mean and p50/p90/p99 over all recorded samples.
+    pub fn summary(&self) -> HrSummary {
+        let mean = if self.count == 0 {
+            Duration::ZERO
+        } else {
+            self.sum / u32::try_from(self.count).unwrap_or(u32::MAX)
+        };
+        HrSummary {
+            count: self.count,
+            min: if self.count == 0 { Duration::ZERO } else { self.min },
+            max: self.max,
+            mean,
+            p50: self.quantile(0.50),
+            p90: self.quantile(0.90),
+            p99: self.quantile(0.99),
+        }
+    }
+}
+
+impl HrTime {
+    /// Sleep each of `periods`, `samples` times over, recording the lag
+    /// between the requested and actual sleep duration while the
+    /// corresponding [`HrPeriod`] is active. Useful for confirming that
+    /// enabling the high resolution timer actually helps on the host it's
+    /// running on.
+    pub fn measure(periods: &[Duration], samples: usize) -> HrStats {
+        let ceiling = periods.iter().copied().max().unwrap_or(Duration::from_millis(1));
+        let mut stats = HrStats::new(ceiling * 4);
+        for &period in periods {
+            let _hrt = Self::get(period);
+            let mut s = Instant::now();
+            for _ in 0..samples {
+                sleep(period);
+                let e = Instant::now();
+                stats.record(period, e - s);
+                s = e;
+            }
+        }
+        stats
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::HrTime;
+    use super::{HrStats, HrTime, Period};
     use std::thread::{sleep, spawn};
     use std::time::{Duration, Instant};
 
@@ -344,20 +696,78 @@ mod test {
     /// A limit for when high resolution timers are disabled.
     const GENEROUS: Duration = Duration::from_millis(30);
 
+    #[test]
+    fn period_quantization() {
+        // Anything at or below the finest interval quantizes to `MIN`.
+        assert_eq!(Period::from(Duration::from_nanos(0)), Period::MIN);
+        assert_eq!(Period::from(Duration::from_micros(250)), Period::MIN);
+
+        // Coarser durations land in a strictly larger bucket.
+        let one_ms = Period::from(Duration::from_millis(1));
+        let two_ms = Period::from(Duration::from_millis(2));
+        assert!(two_ms > one_ms);
+        assert!(two_ms.nanos() > one_ms.nanos());
+
+        // Anything past the top of the range clamps to `MAX`, shared by
+        // every coarser duration rather than overflowing.
+        assert_eq!(Period::from(Duration::from_millis(16)), Period::MAX);
+        assert_eq!(Period::from(Duration::from_secs(1)), Period::MAX);
+
+        // Buckets are spaced a quarter-octave apart.
+        let next = Period(Period::MIN.0 + 1);
+        let ratio = next.nanos() as f64 / Period::MIN.nanos() as f64;
+        assert!((ratio - 2f64.powf(1.0 / 4.0)).abs() < 0.01);
+    }
+
+    #[test]
+    fn hr_stats_summary() {
+        let mut stats = HrStats::new(Duration::from_micros(5));
+        for us in [0, 1, 2, 3, 4, 10] {
+            stats.record(Duration::ZERO, Duration::from_micros(us));
+        }
+
+        let summary = stats.summary();
+        assert_eq!(summary.count, 6);
+        assert_eq!(summary.min, Duration::ZERO);
+        assert_eq!(summary.max, Duration::from_micros(10));
+        assert_eq!(summary.mean, Duration::from_nanos(3_333));
+        assert_eq!(summary.p50, Duration::from_micros(2));
+        // The 10us sample overflowed the 5us histogram, so the high
+        // percentiles can only be reported as a lower bound of the ceiling.
+        assert_eq!(summary.p99, Duration::from_micros(5));
+    }
+
+    #[test]
+    fn hr_stats_empty() {
+        let stats = HrStats::new(Duration::from_micros(1));
+        let summary = stats.summary();
+        assert_eq!(summary.count, 0);
+        assert_eq!(summary.min, Duration::ZERO);
+        assert_eq!(summary.mean, Duration::ZERO);
+    }
+
+    #[test]
+    fn get_hz_matches_frequency() {
+        let hrp = HrTime::get_hz(200);
+        let expected = Period::from(Duration::from_nanos(1_000_000_000 / 200));
+        assert_eq!(hrp.period, expected);
+        assert_eq!(hrp.frequency(), (1_000_000_000 / expected.nanos()) as u32);
+    }
+
     fn check_delays(max_lag: Duration) {
         const DELAYS: &[u64] = &[1, 2, 3, 5, 8, 10, 12, 15, 20, 25, 30];
         let durations = DELAYS.iter().map(|&d| Duration::from_millis(d));
 
+        let mut stats = HrStats::new(max_lag * 4);
         let mut s = Instant::now();
         for d in durations {
             sleep(d);
             let e = Instant::now();
-            let actual = e - s;
-            let lag = actual - d;
-            println!("sleep({:?}) → {:?} Δ{:?}", d, actual, lag);
-            assert!(lag < max_lag);
-            s = Instant::now();
+            stats.record(d, e - s);
+            s = e;
         }
+        println!("{:?}", stats.summary());
+        assert!(stats.summary().max < max_lag);
     }
 
     /// Note that you have to run this test alone or other tests will
@@ -373,6 +783,45 @@ mod test {
         check_delays(ONE_AND_A_BIT);
     }
 
+    /// Unlike `one_ms`, which only checks a bound loose enough that even a
+    /// too-large slack still satisfies it, this compares mean lag with the
+    /// timer on against mean lag with it off, so a slack that makes things
+    /// worse (rather than better) actually fails the test.
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn linux_slack_tightens_lag() {
+        use super::linux;
+
+        if linux::get_timerslack().is_none() {
+            // This environment doesn't support reading/writing timer slack
+            // (e.g. `prctl(PR_GET_TIMERSLACK)` is unavailable), so `HrTime`
+            // never touches it and there's nothing to compare.
+            return;
+        }
+
+        const DELAYS: &[u64] = &[1, 2, 3, 5, 8, 10, 12, 15, 20, 25, 30];
+        let run = || {
+            let mut stats = HrStats::new(GENEROUS);
+            let mut s = Instant::now();
+            for d in DELAYS.iter().map(|&d| Duration::from_millis(d)) {
+                sleep(d);
+                let e = Instant::now();
+                stats.record(d, e - s);
+                s = e;
+            }
+            stats
+        };
+
+        let off = run();
+        let _hrt = HrTime::get(ONE);
+        let on = run();
+        drop(_hrt);
+
+        println!("off: {:?}", off.summary());
+        println!("on: {:?}", on.summary());
+        assert!(on.summary().mean < off.summary().mean);
+    }
+
     #[test]
     fn multithread_baseline() {
         let thr = spawn(move || {
@@ -423,4 +872,25 @@ mod test {
         let _hrt = HrTime::get(Duration::from_secs(1));
         check_delays(GENEROUS);
     }
+
+    #[test]
+    #[cfg(target_os = "macos")]
+    fn with_constraints_restores_previous() {
+        use super::RealtimeConstraints;
+
+        // Keep the shared `HrTime` alive across the override handle's drop,
+        // so its restored `constraints` can still be inspected afterwards.
+        let anchor = HrTime::get(ONE);
+        let default = anchor.hrt.borrow().constraints.constraint_ratio;
+
+        let custom = RealtimeConstraints {
+            constraint_ratio: default + 1.0,
+            ..RealtimeConstraints::default()
+        };
+        let overridden = HrTime::with_constraints(ONE, custom);
+        assert_eq!(overridden.hrt.borrow().constraints.constraint_ratio, custom.constraint_ratio);
+
+        drop(overridden);
+        assert_eq!(anchor.hrt.borrow().constraints.constraint_ratio, default);
+    }
 }
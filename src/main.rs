@@ -1,38 +1,10 @@
-use std::thread::sleep;
-use std::time::{Duration, Instant};
-
-#[cfg(windows)]
-mod win {
-    // TODO (generate bindings properly)
-    pub type UINT = ::std::os::raw::c_uint;
-    pub type MMRESULT = UINT;
-    extern "C" {
-        pub fn timeBeginPeriod(uPeriod: UINT) -> MMRESULT;
-    }
-    extern "C" {
-        pub fn timeEndPeriod(uPeriod: UINT) -> MMRESULT;
-    }
-}
+use hrtimer::HrTime;
+use std::time::Duration;
 
 fn main() {
-    const DELAYS: &[u64] = &[1, 2, 3, 5, 8, 10, 12, 15, 20, 25, 30];
-    let durations = DELAYS.iter().map(|&d| Duration::from_millis(d));
-
-    #[cfg(windows)]
-    unsafe {
-        win::timeBeginPeriod(1)
-    };
-
-    let mut s = Instant::now();
-    for i in durations {
-        sleep(i);
-        let e = Instant::now();
-        println!("sleep({:?}) → {:?} Δ{:?})", i, e - s, e - s - i);
-        s = Instant::now();
-    }
+    const DELAYS_MS: &[u64] = &[1, 2, 3, 5, 8, 10, 12, 15, 20, 25, 30];
+    let periods: Vec<Duration> = DELAYS_MS.iter().map(|&d| Duration::from_millis(d)).collect();
 
-    #[cfg(windows)]
-    unsafe {
-        win::timeEndPeriod(1)
-    };
+    let stats = HrTime::measure(&periods, 10);
+    println!("{:#?}", stats.summary());
 }